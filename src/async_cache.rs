@@ -0,0 +1,155 @@
+// This Source Code is covered under the terms of the Mozilla Public License, v.2.0.
+// A copy of this license can be found in the root directory of this project.  If
+// no copy was found, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! An async, cache-miss-coalescing wrapper around [LruWeightedCache].
+//!
+//! [LruWeightedCache] itself is synchronous and has no notion of concurrent
+//! callers racing to fill the same missing key.  [AsyncLruWeightedCache]
+//! adds that on top: it serializes access behind a `tokio::sync::Mutex` and,
+//! on a miss, lets every caller for the same key await a single shared
+//! `fetch` future instead of each starting (and paying for) their own —
+//! the same shape as the Proxmox `async_lru_cache` layer over its sync LRU.
+//!
+//! This module is only compiled with the `async` feature enabled.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tokio::sync::Mutex;
+
+use crate::{LruError, LruWeightedCache, Weighted};
+
+type InFlight<V, E> = Shared<BoxFuture<'static, Result<V, E>>>;
+
+/// An async cache that de-duplicates concurrent fetches of the same
+/// missing key.
+///
+/// `V` must be `Clone` because the value resolved by a single in-flight
+/// `fetch` is handed to every caller that was waiting on it.
+pub struct AsyncLruWeightedCache<K, V, E> {
+    cache: Mutex<LruWeightedCache<K, V>>,
+    in_flight: Mutex<HashMap<K, InFlight<V, E>>>,
+}
+
+impl<K, V, E> AsyncLruWeightedCache<K, V, E>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Weighted + Clone + Send + Sync + 'static,
+    E: Clone + Send + Sync + 'static,
+{
+    /// Build a new async cache with the same capacity semantics as
+    /// [LruWeightedCache::new].
+    pub fn new(max_count: usize, max_item_weight: usize) -> Result<Self, LruError> {
+        Ok(Self {
+            cache: Mutex::new(LruWeightedCache::new(max_count, max_item_weight)?),
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Fetch `key`, coalescing concurrent misses for the same key into a
+    /// single call to `fetch`.
+    ///
+    /// On a hit, returns a clone of the cached value without calling
+    /// `fetch`.  On a miss, if another caller is already fetching `key`,
+    /// this awaits a clone of their in-flight future instead of starting a
+    /// new one.  Otherwise it calls `fetch`, shares the resulting future
+    /// with any callers that arrive while it's in flight, and on success
+    /// inserts the result into the cache.  The in-flight entry is always
+    /// removed once `fetch` resolves, success or failure, so a failed load
+    /// does not poison the key for subsequent callers.
+    pub async fn get_or_fetch<Fut>(&self, key: K, fetch: impl FnOnce() -> Fut) -> Result<V, E>
+    where
+        Fut: Future<Output = Result<V, E>> + Send + 'static,
+    {
+        if let Some(value) = self.cache.lock().await.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let shared = {
+            let mut in_flight = self.in_flight.lock().await;
+            match in_flight.get(&key) {
+                Some(shared) => shared.clone(),
+                None => {
+                    let shared: InFlight<V, E> = fetch().boxed().shared();
+                    in_flight.insert(key.clone(), shared.clone());
+                    shared
+                }
+            }
+        };
+
+        let result = shared.await;
+
+        if let Ok(ref value) = result {
+            // Another caller may have raced us to insert the same key; that's
+            // harmless, `insert` just replaces the entry.
+            let _ = self.cache.lock().await.insert(key.clone(), value.clone());
+        }
+        self.in_flight.lock().await.remove(&key);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncLruWeightedCache;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn fetches_on_miss_and_caches_the_result() {
+        let cache: AsyncLruWeightedCache<&str, &str, ()> =
+            AsyncLruWeightedCache::new(5, 2).unwrap();
+
+        let value = cache.get_or_fetch("foo", || async { Ok("aa") }).await;
+        assert_eq!(value, Ok("aa"));
+
+        // Second call is a hit; fetch is never invoked.
+        let value = cache
+            .get_or_fetch("foo", || async { unreachable!() })
+            .await;
+        assert_eq!(value, Ok("aa"));
+    }
+
+    #[tokio::test]
+    async fn coalesces_concurrent_misses() {
+        let cache = Arc::new(AsyncLruWeightedCache::<&str, &str, ()>::new(5, 2).unwrap());
+        let fetch_calls = Arc::new(AtomicUsize::new(0));
+
+        let mut handles = Vec::new();
+        for _ in 0..8 {
+            let cache = Arc::clone(&cache);
+            let fetch_calls = Arc::clone(&fetch_calls);
+            handles.push(tokio::spawn(async move {
+                cache
+                    .get_or_fetch("foo", || async move {
+                        fetch_calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::task::yield_now().await;
+                        Ok("42")
+                    })
+                    .await
+            }));
+        }
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Ok("42"));
+        }
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_does_not_poison_the_key() {
+        let cache: AsyncLruWeightedCache<&str, &str, &str> =
+            AsyncLruWeightedCache::new(5, 2).unwrap();
+
+        let err = cache.get_or_fetch("foo", || async { Err("boom") }).await;
+        assert_eq!(err, Err("boom"));
+
+        let ok = cache.get_or_fetch("foo", || async { Ok("aa") }).await;
+        assert_eq!(ok, Ok("aa"));
+    }
+}
@@ -31,9 +31,20 @@
 //! also hold 10 strings of length 10, or 25 strings of length 4, and
 //! so on.  It could not, however, hold 4 strings of length 25: the
 //! `insert()` method will *reject* an object above the `max_weight`.
+//!
+//! ## Async
+//!
+//! Enabling the `async` feature adds
+//! [AsyncLruWeightedCache](async_cache::AsyncLruWeightedCache), a wrapper
+//! that de-duplicates concurrent async cache misses for the same key.
 
+#[cfg(feature = "async")]
+pub mod async_cache;
+
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::ptr;
 
@@ -92,8 +103,9 @@ pub enum LruError {
     NonsenseParameters,
 }
 
-pub struct LruWeightedCache<K, V> {
-    cache: HashMap<LruCacheKey<K>, Box<LruCacheItem<K, V>>>,
+pub struct LruWeightedCache<K, V, S = RandomState> {
+    cache: HashMap<LruCacheKey<K>, Box<LruCacheItem<K, V>>, S>,
+    max_count: usize,
     max_item_weight: usize,
     max_total_weight: usize,
     current_weight: usize,
@@ -101,7 +113,7 @@ pub struct LruWeightedCache<K, V> {
     tail: *mut LruCacheItem<K, V>,
 }
 
-impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
+impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V, RandomState> {
     /// Build a new LRU cache.
     ///
     /// The two values you have to supply, `max_count` and `max_weight`,
@@ -109,18 +121,37 @@ impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
     /// object and if the cache will eject an old object.  The maximum
     /// weight of the cache will be `max_count * max_weight`, but it's
     /// important to understand that `max_count` is the number of
-    /// *maximal-weight* objects the cache can contain.
+    /// *maximal-weight* objects the cache can contain.  `max_count` is
+    /// also enforced on its own: the cache will never hold more than
+    /// `max_count` entries, regardless of how little weight they carry.
     pub fn new(
         max_count: usize,
         max_item_weight: usize,
-    ) -> Result<LruWeightedCache<K, V>, LruError> {
+    ) -> Result<LruWeightedCache<K, V, RandomState>, LruError> {
+        Self::new_with_hasher(max_count, max_item_weight, RandomState::default())
+    }
+}
+
+impl<K: Hash + Eq, V: Weighted, S: BuildHasher> LruWeightedCache<K, V, S> {
+    /// Build a new LRU cache with a custom [BuildHasher](std::hash::BuildHasher).
+    ///
+    /// This is identical to [new](LruWeightedCache::new), except it lets callers
+    /// supply their own hasher (e.g. from `ahash` or `fxhash`) instead of the
+    /// default SipHash-based `RandomState`, which is worth doing for caches
+    /// keyed on cheap-to-hash values like small integers or short strings.
+    pub fn new_with_hasher(
+        max_count: usize,
+        max_item_weight: usize,
+        hasher: S,
+    ) -> Result<LruWeightedCache<K, V, S>, LruError> {
         if max_count == 0 || max_item_weight == 0 {
             return Err(LruError::NonsenseParameters);
         }
 
         let max_total_weight = max_item_weight * max_count;
         let lrucache = LruWeightedCache {
-            cache: HashMap::new(),
+            cache: HashMap::with_hasher(hasher),
+            max_count,
             max_item_weight,
             max_total_weight,
             current_weight: 0,
@@ -137,6 +168,36 @@ impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
         Ok(lrucache)
     }
 
+    /// Resize the cache's capacity in place.
+    ///
+    /// Validates `max_count` and `max_item_weight` the same way
+    /// [new_with_hasher](LruWeightedCache::new_with_hasher) does, recomputes
+    /// `max_total_weight`, and then evicts least-recently-used entries from
+    /// the tail until the cache satisfies both the new weight and count
+    /// limits.  Useful for shrinking a long-lived cache's footprint under
+    /// memory pressure without tearing it down and rebuilding it.
+    pub fn set_capacity(
+        &mut self,
+        max_count: usize,
+        max_item_weight: usize,
+    ) -> Result<(), LruError> {
+        if max_count == 0 || max_item_weight == 0 {
+            return Err(LruError::NonsenseParameters);
+        }
+
+        self.max_count = max_count;
+        self.max_item_weight = max_item_weight;
+        self.max_total_weight = max_item_weight * max_count;
+
+        while self.current_weight > self.max_total_weight || self.len() > self.max_count {
+            unsafe {
+                self.remove((*(*self.tail).prev).key.assume_init_ref());
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns true if the [Weighted](trait.Weighted.html) object is less than
     /// the max weight.
     pub fn will_accept(&mut self, value: &V) -> bool {
@@ -144,23 +205,28 @@ impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
     }
 
     // From the oldest upward, discard objects until there's enough
-    // room for the requested object.
+    // room for the requested object, both by weight and by count.
     fn eject(&mut self, value: &V, node_ptr: &Option<*mut LruCacheItem<K, V>>) {
-        // Must keep track of our own notion of current weight, because
-        // we have not yet ejected this value from the cache.
+        // Must keep track of our own notion of current weight and count,
+        // because we have not yet ejected this value from the cache.
 
         let mut current_weight = self.current_weight;
+        // The number of entries already in the cache that are not the
+        // candidate being replaced, i.e. the count before this insert.
+        let mut count = self.len();
         if let Some(node_ptr) = *node_ptr {
             // Remove the size of the value for an existing candidate node.
             unsafe { current_weight -= (*node_ptr).value.assume_init_ref().weight() };
+            count -= 1;
         }
 
-        while current_weight + value.weight() > self.max_total_weight {
+        while current_weight + value.weight() > self.max_total_weight || count >= self.max_count {
             let v = unsafe {
                 self.remove((*(*self.tail).prev).key.assume_init_ref())
                     .unwrap()
             };
             current_weight -= v.weight();
+            count -= 1;
         }
     }
 
@@ -207,6 +273,85 @@ impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
         Ok(())
     }
 
+    /// Return the cached value for `key`, computing and inserting it via `f`
+    /// on a miss.
+    ///
+    /// On a hit, the existing value is promoted and returned without calling
+    /// `f`.  On a miss, `f` is called to build the value, which is then
+    /// subject to the same [will_accept](LruWeightedCache::will_accept)
+    /// check and ejection as [insert](LruWeightedCache::insert).  This saves
+    /// callers the double lookup of `contains_key` followed by `insert`, and
+    /// guarantees `f` (e.g. reading and parsing a document) is skipped
+    /// entirely on hits.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> Result<&V, LruError>
+    where
+        F: FnOnce() -> V,
+    {
+        let existing = self.cache.get_mut(&LruCacheKey { key: &key }).map(|node| {
+            let node_ptr: *mut LruCacheItem<K, V> = &mut **node;
+            node_ptr
+        });
+
+        if let Some(node_ptr) = existing {
+            self.promote(node_ptr);
+            return Ok(unsafe { (*node_ptr).value.assume_init_ref() });
+        }
+
+        let value = f();
+        if !self.will_accept(&value) {
+            return Err(LruError::ExceedsMaximumWeight);
+        }
+
+        self.eject(&value, &None);
+        self.current_weight += value.weight();
+        let mut node = Box::new(LruCacheItem::new(key, value));
+        let node_ptr: *mut LruCacheItem<K, V> = &mut *node;
+        self.attach(node_ptr);
+        let keyref = unsafe { (*node_ptr).key.assume_init_ref() };
+        self.cache.insert(LruCacheKey { key: keyref }, node);
+
+        Ok(unsafe { (*node_ptr).value.assume_init_ref() })
+    }
+
+    /// Mutate a cached value in place, automatically recomputing its weight.
+    ///
+    /// `get` deliberately has no mutable counterpart: a value's
+    /// [weight](trait.Weighted.html) can change, and mutating it behind the
+    /// cache's back would silently desync `current_weight` from the real
+    /// total.  `mutate` keeps the two in sync instead: it subtracts the
+    /// value's old weight, runs `f` against it, adds the new weight back,
+    /// promotes the entry for recency, and then ejects *other* entries from
+    /// the tail if the value grew past the remaining capacity.  Returns
+    /// `None` if `key` is not present.
+    pub fn mutate<F, R>(&mut self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        let lkey = LruCacheKey { key };
+        let node_ptr = self.cache.get_mut(&lkey).map(|node| {
+            let node_ptr: *mut LruCacheItem<K, V> = &mut **node;
+            node_ptr
+        })?;
+
+        let result = unsafe {
+            let value = (*node_ptr).value.assume_init_mut();
+            self.current_weight -= value.weight();
+            let result = f(value);
+            self.current_weight += value.weight();
+            result
+        };
+
+        self.promote(node_ptr);
+
+        while self.current_weight > self.max_total_weight && self.len() > 1 {
+            unsafe {
+                self.remove((*(*self.tail).prev).key.assume_init_ref());
+            }
+        }
+
+        Some(result)
+    }
+
     pub fn get(&mut self, key: &K) -> Option<&V> {
         let lkey = LruCacheKey { key };
         self.cache
@@ -214,6 +359,28 @@ impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
             .map(|v| unsafe { v.value.assume_init_ref() })
     }
 
+    /// Look up a value without promoting it, i.e. without moving it to the
+    /// front of the LRU list.  Useful for monitoring/eviction-aware code
+    /// that wants to inspect the cache without the side effect of changing
+    /// what gets evicted next.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let lkey = LruCacheKey { key };
+        self.cache
+            .get(&lkey)
+            .map(|v| unsafe { v.value.assume_init_ref() })
+    }
+
+    /// Iterate over cache entries from most- to least-recently-used,
+    /// without promoting any of them.  Walks the intrusive list directly
+    /// (rather than the `HashMap`) so it can borrow `&self` immutably.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        Iter {
+            next: unsafe { (*self.head).next },
+            tail: self.tail,
+            _marker: PhantomData,
+        }
+    }
+
     pub fn remove(&mut self, key: &K) -> Option<V> {
         let key = LruCacheKey { key };
         match self.cache.remove(&key) {
@@ -271,8 +438,33 @@ impl<K: Hash + Eq, V: Weighted> LruWeightedCache<K, V> {
     }
 }
 
+/// A read-only iterator over cache entries, most- to least-recently-used.
+///
+/// Yielded by [LruWeightedCache::iter]; walking it does not affect recency.
+pub struct Iter<'a, K, V> {
+    next: *const LruCacheItem<K, V>,
+    tail: *const LruCacheItem<K, V>,
+    _marker: PhantomData<&'a (K, V)>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.tail {
+            return None;
+        }
+
+        unsafe {
+            let node = &*self.next;
+            self.next = node.next;
+            Some((node.key.assume_init_ref(), node.value.assume_init_ref()))
+        }
+    }
+}
+
 #[doc(hidden)]
-impl<K, V> Drop for LruWeightedCache<K, V> {
+impl<K, V, S> Drop for LruWeightedCache<K, V, S> {
     fn drop(&mut self) {
         self.cache.values_mut().for_each(|e| unsafe {
             ptr::drop_in_place(e.key.as_mut_ptr());
@@ -290,12 +482,13 @@ impl<K, V> Drop for LruWeightedCache<K, V> {
 // The compiler does not automatically derive Send and Sync for LruCache because it contains
 // raw pointers. The raw pointers are safely encapsulated by LruCache though so we can
 // implement Send and Sync for it below.
-unsafe impl<K: Send, V: Send> Send for LruWeightedCache<K, V> {}
-unsafe impl<K: Sync, V: Sync> Sync for LruWeightedCache<K, V> {}
+unsafe impl<K: Send, V: Send, S: Send> Send for LruWeightedCache<K, V, S> {}
+unsafe impl<K: Sync, V: Sync, S: Sync> Sync for LruWeightedCache<K, V, S> {}
 
-impl<K: Hash + Eq, V> std::fmt::Debug for LruWeightedCache<K, V> {
+impl<K: Hash + Eq, V, S> std::fmt::Debug for LruWeightedCache<K, V, S> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("LruWeightedCache")
+            .field("max_count", &self.max_count)
             .field("max_item_weight", &self.max_item_weight)
             .field("max_total_weight", &self.max_total_weight)
             .field("current_weight", &self.current_weight)
@@ -375,24 +568,42 @@ mod tests {
 
     #[test]
     fn eject_by_weight() {
+        // Every item weighs exactly max_item_weight, so filling the cache
+        // reaches the weight cap and the count cap together: that's the
+        // dual-limit design's own invariant, max_total_weight == max_count
+        // * max_item_weight. Fewer, same-size items make the sliding window
+        // easy to verify by hand.
         let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(3, 4).unwrap();
-        for i in &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l"] {
+        for i in &["aaaa", "bbbb", "cccc", "dddd", "eeee"] {
             let _ = cache.insert(i, i);
         }
-        let _ = cache.insert("z", "zzz");
         assert_eq!(cache.weight(), 12); // 3 * 4
-        assert_eq!(cache.len(), 10); // three items should have been removed, then one added.
+        assert_eq!(cache.len(), 3); // sliding window of 3 max-weight items.
+        assert!(cache.contains_key(&"cccc"));
+        assert!(cache.contains_key(&"dddd"));
+        assert!(cache.contains_key(&"eeee"));
+        assert!(!cache.contains_key(&"aaaa"));
+        assert!(!cache.contains_key(&"bbbb"));
     }
 
     #[test]
     fn replace_by_weight() {
+        // Same setup as eject_by_weight, but the final entry is a replace
+        // (same weight, existing key) rather than a new insert: it must
+        // promote the entry and leave the cache's weight/count unchanged.
         let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(3, 4).unwrap();
-        for i in &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l"] {
+        for i in &["aaaa", "bbbb", "cccc", "dddd", "eeee"] {
             let _ = cache.insert(i, i);
         }
-        let _ = cache.insert("l", "zzz");
-        assert_eq!(cache.weight(), 12); // 3 * 4
-        assert_eq!(cache.len(), 10); // three items should have been removed, then one added.
+        assert_eq!(cache.weight(), 12);
+        assert_eq!(cache.len(), 3);
+
+        let _ = cache.insert("eeee", "zzzz");
+        assert_eq!(cache.weight(), 12); // unchanged: still 3 max-weight entries.
+        assert_eq!(cache.len(), 3);
+        assert!(cache.contains_key(&"cccc"));
+        assert!(cache.contains_key(&"dddd"));
+        assert_eq!(cache.get(&"eeee"), Some(&"zzzz"));
     }
 
     #[test]
@@ -409,6 +620,165 @@ mod tests {
         assert!(cache.get(&"bar").is_none());
     }
 
+    #[test]
+    fn use_a_custom_hasher() {
+        use std::collections::hash_map::RandomState;
+
+        let mut cache: LruWeightedCache<&str, &str, RandomState> =
+            LruWeightedCache::new_with_hasher(5, 2, RandomState::new()).unwrap();
+        let _ = cache.insert("foo", "aa");
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&"foo") == Some(&"aa"));
+    }
+
+    #[test]
+    fn peek_does_not_promote() {
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(2, 2).unwrap();
+        let _ = cache.insert("foo", "aa");
+        let _ = cache.insert("bar", "bb");
+        assert_eq!(cache.peek(&"foo"), Some(&"aa"));
+        // "foo" was peeked, not promoted, so it's still the least-recently-used
+        // entry and should be the one evicted.
+        let _ = cache.insert("baz", "cc");
+        assert!(!cache.contains_key(&"foo"));
+        assert!(cache.contains_key(&"bar"));
+        assert!(cache.contains_key(&"baz"));
+    }
+
+    #[test]
+    fn iter_yields_most_to_least_recently_used() {
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(5, 2).unwrap();
+        let _ = cache.insert("foo", "aa");
+        let _ = cache.insert("bar", "bb");
+        let _ = cache.insert("baz", "cc");
+
+        let entries: Vec<_> = cache.iter().collect();
+        assert_eq!(
+            entries,
+            vec![(&"baz", &"cc"), (&"bar", &"bb"), (&"foo", &"aa")]
+        );
+    }
+
+    #[test]
+    fn get_or_insert_with_computes_only_on_miss() {
+        use std::cell::Cell;
+
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(5, 2).unwrap();
+        let calls = Cell::new(0);
+
+        let value = *cache
+            .get_or_insert_with("foo", || {
+                calls.set(calls.get() + 1);
+                "aa"
+            })
+            .unwrap();
+        assert_eq!(value, "aa");
+        assert_eq!(calls.get(), 1);
+
+        let value = *cache
+            .get_or_insert_with("foo", || {
+                calls.set(calls.get() + 1);
+                "should not run"
+            })
+            .unwrap();
+        assert_eq!(value, "aa");
+        assert_eq!(calls.get(), 1); // second call was a hit; f() was not invoked.
+    }
+
+    #[test]
+    fn get_or_insert_with_rejects_oversized_value() {
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(5, 2).unwrap();
+        let result = cache.get_or_insert_with("foo", || "too long");
+        assert_eq!(result, Err(LruError::ExceedsMaximumWeight));
+        assert!(!cache.contains_key(&"foo"));
+    }
+
+    #[test]
+    fn mutate_recomputes_weight() {
+        let mut cache: LruWeightedCache<&str, Vec<u8>> = LruWeightedCache::new(5, 4).unwrap();
+        let _ = cache.insert("foo", vec![1, 2]);
+        let len = cache.mutate(&"foo", |v| {
+            v.push(3);
+            v.len()
+        });
+        assert_eq!(len, Some(3));
+        assert_eq!(cache.weight(), 3);
+    }
+
+    #[test]
+    fn mutate_ejects_other_entries_on_growth() {
+        let mut cache: LruWeightedCache<&str, Vec<u8>> = LruWeightedCache::new(2, 4).unwrap();
+        let _ = cache.insert("foo", vec![1, 2]);
+        let _ = cache.insert("bar", vec![1, 2, 3]);
+        assert_eq!(cache.weight(), 5);
+
+        cache.mutate(&"foo", |v| v.extend_from_slice(&[9, 9, 9, 9, 9]));
+        assert_eq!(cache.len(), 1); // "bar" should have been ejected to make room.
+        assert_eq!(cache.weight(), 7);
+        assert!(cache.contains_key(&"foo"));
+        assert!(!cache.contains_key(&"bar"));
+    }
+
+    #[test]
+    fn mutate_missing_key_is_noop() {
+        let mut cache: LruWeightedCache<&str, Vec<u8>> = LruWeightedCache::new(5, 4).unwrap();
+        assert_eq!(cache.mutate(&"foo", |v| v.len()), None);
+    }
+
+    #[test]
+    fn eject_by_count() {
+        // Plenty of weight budget (100), but only 3 entries allowed.
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(3, 100).unwrap();
+        for i in &["a", "b", "c", "d", "e"] {
+            let _ = cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 3); // capped at max_count, even though weight is tiny.
+        assert!(cache.contains_key(&"e"));
+        assert!(cache.contains_key(&"d"));
+        assert!(cache.contains_key(&"c"));
+        assert!(!cache.contains_key(&"a"));
+        assert!(!cache.contains_key(&"b"));
+    }
+
+    #[test]
+    fn set_capacity_shrinks_by_weight() {
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(12, 1).unwrap();
+        for i in &["a", "b", "c", "d", "e"] {
+            let _ = cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 5);
+
+        assert_eq!(
+            cache.set_capacity(12, 0),
+            Err(LruError::NonsenseParameters)
+        );
+        assert_eq!(
+            cache.set_capacity(0, 1),
+            Err(LruError::NonsenseParameters)
+        );
+
+        assert_eq!(cache.set_capacity(3, 1), Ok(()));
+        assert_eq!(cache.weight(), 3);
+        assert_eq!(cache.len(), 3); // oldest entries evicted to fit the new weight cap.
+        assert!(cache.contains_key(&"e"));
+        assert!(cache.contains_key(&"d"));
+        assert!(cache.contains_key(&"c"));
+    }
+
+    #[test]
+    fn set_capacity_shrinks_by_count() {
+        let mut cache: LruWeightedCache<&str, &str> = LruWeightedCache::new(5, 100).unwrap();
+        for i in &["a", "b", "c", "d", "e"] {
+            let _ = cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 5);
+
+        cache.set_capacity(2, 100).unwrap();
+        assert_eq!(cache.len(), 2); // plenty of weight budget, but count now caps it.
+        assert!(cache.contains_key(&"e"));
+        assert!(cache.contains_key(&"d"));
+    }
+
     #[test]
     fn catch_errant_nonsense() {
         let cache = LruWeightedCache::<&str, &str>::new(0, 0);